@@ -0,0 +1,53 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Interface to the Linux spidev driver for communicating via SPI
+//! from userspace, as described by the kernel documentation at
+//! `Documentation/spi/spidev`.
+
+#[macro_use]
+extern crate bitflags;
+#[macro_use]
+extern crate nix;
+
+mod spidevioctl;
+
+pub use spidevioctl::{SpidevTransfer, TxBuf, RxBuf};
+
+bitflags! {
+    /// Flags used when configuring the SPI bus mode via `spidevioctl::set_mode`.
+    ///
+    /// These flags correspond directly to the `SPI_*` mode bits defined in
+    /// `linux/spi/spidev.h`.
+    pub flags SpiModeFlags: u32 {
+        /// Clock Phase
+        const SPI_CPHA = 0x01,
+        /// Clock Polarity
+        const SPI_CPOL = 0x02,
+        /// Chipselect Active High?
+        const SPI_CS_HIGH = 0x04,
+        /// Per-word Bits On Wire
+        const SPI_LSB_FIRST = 0x08,
+        /// Three Wire Mode (SI/SO Signals Shared)
+        const SPI_3WIRE = 0x10,
+        /// Loopback Mode
+        const SPI_LOOP = 0x20,
+        /// 1 dev/bus, no Chipselect
+        const SPI_NO_CS = 0x40,
+        /// Slave Pulls Low To Pause
+        const SPI_READY = 0x80,
+        /// Transmit With 2 Wires
+        const SPI_TX_DUAL = 0x100,
+        /// Transmit With 4 Wires
+        const SPI_TX_QUAD = 0x200,
+        /// Receive With 2 Wires
+        const SPI_RX_DUAL = 0x400,
+        /// Receive With 4 Wires
+        const SPI_RX_QUAD = 0x800,
+    }
+}
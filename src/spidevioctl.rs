@@ -8,11 +8,21 @@
 
 #![allow(dead_code)]
 
+use std::cmp;
+use std::fs::File;
 use std::io;
+use std::io::Read;
 use std::mem;
 use std::os::unix::prelude::*;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use super::SpiModeFlags;
 
+/// Fallback transfer size limit used when the spidev driver's `bufsiz`
+/// module parameter cannot be determined.  This matches the kernel
+/// driver's own default.
+const DEFAULT_BUFSIZ: usize = 4096;
+
 fn from_nix_error(err: ::nix::Error) -> io::Error {
     io::Error::from_raw_os_error(err.errno() as i32)
 }
@@ -41,6 +51,13 @@ fn from_nix_result<T>(res: ::nix::Result<T>) -> io::Result<T> {
 /// @delay_usecs: If nonzero, how long to delay after the last bit transfer
 ///      before optionally deselecting the device before the next transfer.
 /// @cs_change: True to deselect device before starting the next transfer.
+/// @tx_nbits: Number of bits used for writing.  If 0 the default
+///      (SPI_NBITS_SINGLE) is used.
+/// @rx_nbits: Number of bits used for reading.  If 0 the default
+///      (SPI_NBITS_SINGLE) is used.
+/// @word_delay_usecs: If nonzero, how long to delay after the end of a
+///      word before starting the next word, for slow devices that need a
+///      gap between words of a single transfer.
 ///
 /// This structure is mapped directly to the kernel spi_transfer structure;
 /// the fields have the same meanings, except of course that the pointers
@@ -76,7 +93,10 @@ pub struct spi_ioc_transfer {
     pub delay_usecs: u16,
     pub bits_per_word: u8,
     pub cs_change: u8,
-    pub pad: u32,
+    pub tx_nbits: u8,
+    pub rx_nbits: u8,
+    pub word_delay_usecs: u8,
+    pub pad: u8,
 }
 
 mod ioctl {
@@ -112,59 +132,185 @@ mod ioctl {
     ioctl!(write buf spidev_transfer_buf with SPI_IOC_MAGIC, SPI_IOC_NR_TRANSFER; spi_ioc_transfer);
 }
 
+/// A transmit buffer that is either owned by the `SpidevTransfer` or
+/// borrowed from the caller for the lifetime of the transfer.
+#[derive(Debug)]
+pub enum TxBuf<'a> {
+    Owned(Box<[u8]>),
+    Borrowed(&'a [u8]),
+}
+
+impl<'a> TxBuf<'a> {
+    /// View the bytes that will be shifted out, regardless of whether this
+    /// buffer is owned or borrowed.
+    pub fn as_slice(&self) -> &[u8] {
+        match *self {
+            TxBuf::Owned(ref buf) => buf,
+            TxBuf::Borrowed(buf) => buf,
+        }
+    }
+
+    fn as_ptr(&self) -> *const u8 {
+        self.as_slice().as_ptr()
+    }
+}
+
+/// A receive buffer that is either owned by the `SpidevTransfer` or
+/// borrowed from the caller for the lifetime of the transfer.
+#[derive(Debug)]
+pub enum RxBuf<'a> {
+    Owned(Box<[u8]>),
+    Borrowed(&'a mut [u8]),
+}
+
+impl<'a> RxBuf<'a> {
+    /// View the bytes received by the transfer, regardless of whether this
+    /// buffer is owned or borrowed.
+    pub fn as_slice(&self) -> &[u8] {
+        match *self {
+            RxBuf::Owned(ref buf) => buf,
+            RxBuf::Borrowed(ref buf) => buf,
+        }
+    }
+
+    /// Mutably view the bytes received by the transfer, regardless of
+    /// whether this buffer is owned or borrowed.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        match *self {
+            RxBuf::Owned(ref mut buf) => buf,
+            RxBuf::Borrowed(ref mut buf) => buf,
+        }
+    }
+
+    fn as_ptr(&self) -> *const u8 {
+        self.as_slice().as_ptr()
+    }
+}
+
 /// Representation of a spidev transfer that is shared
 /// with external users
 #[derive(Default)]
-pub struct SpidevTransfer {
-    pub tx_buf: Option<Box<[u8]>>,
-    pub rx_buf: Option<Box<[u8]>>,
+pub struct SpidevTransfer<'a> {
+    pub tx_buf: Option<TxBuf<'a>>,
+    pub rx_buf: Option<RxBuf<'a>>,
     len: u32,
     speed_hz: u32,
     delay_usecs: u16,
     bits_per_word: u8,
     cs_change: u8,
-    pad: u32,
+    tx_nbits: u8,
+    rx_nbits: u8,
+    word_delay_usecs: u8,
+    pad: u8,
 }
 
-impl SpidevTransfer {
-    pub fn read(length: usize) -> SpidevTransfer {
+impl<'a> SpidevTransfer<'a> {
+    pub fn read(length: usize) -> SpidevTransfer<'static> {
         SpidevTransfer {
             tx_buf: None,
-            rx_buf: Some(vec![0u8; length].into_boxed_slice()),
+            rx_buf: Some(RxBuf::Owned(vec![0u8; length].into_boxed_slice())),
             len: length as u32,
             ..Default::default()
         }
     }
 
-    pub fn write(tx_buf: &[u8]) -> SpidevTransfer {
-        let len = tx_buf.len();
-        let rx_buf_vec: Vec<u8> = vec![0; len];
-        let mut tx_buf_vec = Vec::with_capacity(len);
-        for i in 0..len {
-            tx_buf_vec.push(tx_buf[i]);
-        }
+    pub fn write(tx_buf: &[u8]) -> SpidevTransfer<'static> {
+        SpidevTransfer::duplex(tx_buf)
+    }
 
+    /// Perform a true full-duplex exchange: `tx_buf` is written out while an
+    /// equal-sized, freshly allocated `rx_buf` captures the data shifted in
+    /// at the same time; the received bytes can be read back out of the
+    /// resulting `SpidevTransfer`'s `rx_buf`.  `write` builds this same
+    /// transfer.
+    pub fn duplex(tx_buf: &[u8]) -> SpidevTransfer<'static> {
         SpidevTransfer {
-            tx_buf: Some(tx_buf_vec.into_boxed_slice()),
-            rx_buf: Some(rx_buf_vec.into_boxed_slice()),
+            tx_buf: Some(TxBuf::Owned(tx_buf.to_vec().into_boxed_slice())),
+            rx_buf: Some(RxBuf::Owned(vec![0u8; tx_buf.len()].into_boxed_slice())),
             len: tx_buf.len() as u32,
             ..Default::default()
         }
     }
 
+    /// Zero-copy full-duplex exchange: `tx_buf` and `rx_buf` are borrowed
+    /// from the caller for the lifetime of the transfer instead of being
+    /// copied into freshly allocated buffers, so a high-rate loop can reuse
+    /// the same buffers across calls without per-transfer heap allocation.
+    ///
+    /// `tx_buf` and `rx_buf` must be the same length.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tx_buf.len() != rx_buf.len()`.
+    pub fn duplex_borrowed(tx_buf: &'a [u8], rx_buf: &'a mut [u8]) -> SpidevTransfer<'a> {
+        assert_eq!(tx_buf.len(), rx_buf.len());
+        let len = tx_buf.len() as u32;
+        SpidevTransfer {
+            tx_buf: Some(TxBuf::Borrowed(tx_buf)),
+            rx_buf: Some(RxBuf::Borrowed(rx_buf)),
+            len: len,
+            ..Default::default()
+        }
+    }
+
     pub fn cs_change(mut self, cs_change: bool) -> Self {
         self.cs_change = if cs_change { 1 } else { 0 };
         self
     }
 
+    /// Override the device's default bitrate for this transfer only.
+    pub fn speed_hz(mut self, speed_hz: u32) -> Self {
+        self.speed_hz = speed_hz;
+        self
+    }
+
+    /// If nonzero, how long to delay, in microseconds, after the last bit
+    /// transfer before optionally deselecting the device before the next
+    /// transfer.
+    pub fn delay_usecs(mut self, delay_usecs: u16) -> Self {
+        self.delay_usecs = delay_usecs;
+        self
+    }
+
+    /// Override the device's default word size for this transfer only.
+    pub fn bits_per_word(mut self, bits_per_word: u8) -> Self {
+        self.bits_per_word = bits_per_word;
+        self
+    }
+
+    /// Set the number of data lines used to write this transfer, for
+    /// Dual/Quad SPI devices.  Use the `SPI_TX_DUAL`/`SPI_TX_QUAD` bits of
+    /// `SpiModeFlags` to put the bus into the matching mode.
+    pub fn tx_nbits(mut self, tx_nbits: u8) -> Self {
+        self.tx_nbits = tx_nbits;
+        self
+    }
+
+    /// Set the number of data lines used to read this transfer, for
+    /// Dual/Quad SPI devices.  Use the `SPI_RX_DUAL`/`SPI_RX_QUAD` bits of
+    /// `SpiModeFlags` to put the bus into the matching mode.
+    pub fn rx_nbits(mut self, rx_nbits: u8) -> Self {
+        self.rx_nbits = rx_nbits;
+        self
+    }
+
+    /// If nonzero, how long to delay, in microseconds, after the end of
+    /// each word before starting the next one.  Needed by some
+    /// microcontroller-as-slave protocols that require a gap between
+    /// words within a single transfer.
+    pub fn word_delay_usecs(mut self, word_delay_usecs: u8) -> Self {
+        self.word_delay_usecs = word_delay_usecs;
+        self
+    }
+
     fn as_spi_ioc_transfer(&self) -> spi_ioc_transfer {
         spi_ioc_transfer {
             tx_buf: match self.tx_buf {
-                Some(ref bufbox) => bufbox.as_ptr() as u64,
+                Some(ref buf) => buf.as_ptr() as u64,
                 None => 0,
             },
             rx_buf: match self.rx_buf {
-                Some(ref bufbox) => bufbox.as_ptr() as u64,
+                Some(ref buf) => buf.as_ptr() as u64,
                 None => 0,
             },
             len: self.len,
@@ -172,6 +318,9 @@ impl SpidevTransfer {
             delay_usecs: self.delay_usecs,
             bits_per_word: self.bits_per_word,
             cs_change: self.cs_change,
+            tx_nbits: self.tx_nbits,
+            rx_nbits: self.rx_nbits,
+            word_delay_usecs: self.word_delay_usecs,
             pad: self.pad,
         }
     }
@@ -236,19 +385,115 @@ pub fn set_max_speed_hz(fd: RawFd, max_speed_hz: u32) -> io::Result<()> {
     Ok(())
 }
 
-pub fn transfer(fd: RawFd, transfer: &mut SpidevTransfer) -> io::Result<()> {
-    let mut raw_transfer = transfer.as_spi_ioc_transfer();
+// Cached result of querying the spidev driver's bufsiz module parameter.
+// Zero means "not yet looked up"; this is safe to race on since every
+// racing reader computes the same value.
+static MAX_TRANSFER_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+// The spidev driver rejects any single transfer larger than its `bufsiz`
+// module parameter (4096 bytes by default), so we need to know that limit
+// in order to split oversized transfers into chunks it will accept.
+fn max_transfer_size() -> usize {
+    let cached = MAX_TRANSFER_SIZE.load(Ordering::Relaxed);
+    if cached != 0 {
+        return cached;
+    }
+
+    let bufsiz = read_bufsiz_param().unwrap_or(DEFAULT_BUFSIZ);
+    MAX_TRANSFER_SIZE.store(bufsiz, Ordering::Relaxed);
+    bufsiz
+}
+
+fn read_bufsiz_param() -> Option<usize> {
+    let mut contents = String::new();
+    let mut f = match File::open("/sys/module/spidev/parameters/bufsiz") {
+        Ok(f) => f,
+        Err(_) => return None,
+    };
+    if f.read_to_string(&mut contents).is_err() {
+        return None;
+    }
+    // A bufsiz of 0 is a valid module parameter value but means the driver
+    // rejects every nonzero-length transfer outright, not that there is no
+    // limit; chunking against a limit of 0 would never make progress, so
+    // treat it the same as a missing/unparsable value and fall back to the
+    // default instead.
+    match contents.trim().parse() {
+        Ok(0) => None,
+        Ok(bufsiz) => Some(bufsiz),
+        Err(_) => None,
+    }
+}
+
+// Split `transfer` into a sequence of `spi_ioc_transfer`s that are each no
+// larger than `max_len`, pointing into the original tx/rx buffers so the
+// kernel reads and writes the caller's memory directly.  `cs_change` is
+// cleared on every fragment but the last so the chip stays selected across
+// the split, keeping the operation atomic from the device's perspective.
+fn to_raw_transfers<'a>(transfer: &SpidevTransfer<'a>, max_len: usize) -> Vec<spi_ioc_transfer> {
+    let total_len = transfer.len as usize;
+    // max_len == 0 would make the chunking loop below spin forever without
+    // advancing `offset`; fall back to the driver's default rather than
+    // hang, since a real 0 means "reject everything" anyway.
+    let max_len = if max_len == 0 { DEFAULT_BUFSIZ } else { max_len };
+    if total_len <= max_len {
+        return vec![transfer.as_spi_ioc_transfer()];
+    }
+
+    let tx_ptr = match transfer.tx_buf {
+        Some(ref buf) => buf.as_ptr(),
+        None => ptr::null(),
+    };
+    let rx_ptr = match transfer.rx_buf {
+        Some(ref buf) => buf.as_ptr(),
+        None => ptr::null(),
+    };
+
+    let mut raw_transfers = Vec::new();
+    let mut offset = 0usize;
+    while offset < total_len {
+        let chunk_len = cmp::min(max_len, total_len - offset);
+        let is_last = offset + chunk_len >= total_len;
+        raw_transfers.push(spi_ioc_transfer {
+            tx_buf: if tx_ptr.is_null() { 0 } else { unsafe { tx_ptr.add(offset) as u64 } },
+            rx_buf: if rx_ptr.is_null() { 0 } else { unsafe { rx_ptr.add(offset) as u64 } },
+            len: chunk_len as u32,
+            speed_hz: transfer.speed_hz,
+            delay_usecs: transfer.delay_usecs,
+            bits_per_word: transfer.bits_per_word,
+            cs_change: if is_last { transfer.cs_change } else { 0 },
+            tx_nbits: transfer.tx_nbits,
+            rx_nbits: transfer.rx_nbits,
+            word_delay_usecs: transfer.word_delay_usecs,
+            pad: transfer.pad,
+        });
+        offset += chunk_len;
+    }
+    raw_transfers
+}
 
+pub fn transfer<'a>(fd: RawFd, transfer: &mut SpidevTransfer<'a>) -> io::Result<()> {
     // The kernel will directly modify the rx_buf of the SpidevTransfer
     // rx_buf if present, so there is no need to do any additional work
-    try!(from_nix_result(unsafe { ioctl::spidev_transfer(fd, &mut raw_transfer) }));
+    let mut raw_transfers = to_raw_transfers(transfer, max_transfer_size()).into_boxed_slice();
+
+    if raw_transfers.len() == 1 {
+        try!(from_nix_result(unsafe { ioctl::spidev_transfer(fd, &mut raw_transfers[0]) }));
+    } else {
+        let tot_size = raw_transfers.len() * mem::size_of::<spi_ioc_transfer>();
+        try!(from_nix_result(unsafe {
+            ioctl::spidev_transfer_buf(fd, raw_transfers.as_mut_ptr(), tot_size)
+        }));
+    }
     Ok(())
 }
 
-pub fn transfer_multiple(fd: RawFd, transfers: &Vec<SpidevTransfer>) -> io::Result<()> {
-    // create a boxed slice containing several spi_ioc_transfers
+pub fn transfer_multiple<'a>(fd: RawFd, transfers: &Vec<SpidevTransfer<'a>>) -> io::Result<()> {
+    // create a boxed slice containing several spi_ioc_transfers, splitting
+    // any transfer larger than the driver's bufsiz limit into fragments
+    let max_len = max_transfer_size();
     let mut raw_transfers = transfers.iter()
-                                     .map(|transfer| transfer.as_spi_ioc_transfer())
+                                     .flat_map(|transfer| to_raw_transfers(transfer, max_len))
                                      .collect::<Vec<_>>()
                                      .into_boxed_slice();
     let tot_size = raw_transfers.len() * mem::size_of::<spi_ioc_transfer>();
@@ -258,3 +503,54 @@ pub fn transfer_multiple(fd: RawFd, transfers: &Vec<SpidevTransfer>) -> io::Resu
     }));
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_transfer_thats_an_exact_multiple_of_max_len() {
+        let transfer = SpidevTransfer::write(&[0u8; 8]);
+        let chunks = to_raw_transfers(&transfer, 4);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].len, 4);
+        assert_eq!(chunks[1].len, 4);
+    }
+
+    #[test]
+    fn chunks_transfer_with_a_remainder() {
+        let transfer = SpidevTransfer::write(&[0u8; 10]);
+        let chunks = to_raw_transfers(&transfer, 4);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].len, 4);
+        assert_eq!(chunks[1].len, 4);
+        assert_eq!(chunks[2].len, 2);
+    }
+
+    #[test]
+    fn only_last_chunk_keeps_cs_change() {
+        let transfer = SpidevTransfer::write(&[0u8; 10]).cs_change(true);
+        let chunks = to_raw_transfers(&transfer, 4);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].cs_change, 0);
+        assert_eq!(chunks[1].cs_change, 0);
+        assert_eq!(chunks[2].cs_change, 1);
+    }
+
+    #[test]
+    fn transfer_of_exactly_max_len_is_not_chunked() {
+        let transfer = SpidevTransfer::write(&[0u8; 4]).cs_change(true);
+        let chunks = to_raw_transfers(&transfer, 4);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].len, 4);
+        assert_eq!(chunks[0].cs_change, 1);
+    }
+
+    #[test]
+    fn max_len_of_zero_falls_back_to_default_bufsiz_instead_of_hanging() {
+        let transfer = SpidevTransfer::write(&[0u8; 10]);
+        let chunks = to_raw_transfers(&transfer, 0);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].len, 10);
+    }
+}